@@ -0,0 +1,4 @@
+fn main() {
+    // Needed for ttl.rs, which calls res_ninit()/res_nquery()/res_nclose() directly.
+    println!("cargo:rustc-link-lib=resolv");
+}
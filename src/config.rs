@@ -0,0 +1,123 @@
+// Tunables read from the environment at load time.
+
+use std::env;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+// Number of independent cache shards, each guarded by its own mutex.
+const DEFAULT_SHARD_COUNT: usize = 16;
+
+// Bounds applied to a TTL probed from the wire when TTL mode is enabled.
+const DEFAULT_TTL_FLOOR_MS: u64 = 1_000;
+const DEFAULT_TTL_CEILING_MS: u64 = 300_000;
+
+// 0 means unlimited.
+const DEFAULT_MAX_ENTRIES: usize = 0;
+
+static SHARD_COUNT: AtomicUsize = AtomicUsize::new(DEFAULT_SHARD_COUNT);
+static DEEP_COPY_MODE: AtomicBool = AtomicBool::new(false);
+static USE_DNS_TTL: AtomicBool = AtomicBool::new(false);
+static TTL_FLOOR_MS: AtomicU64 = AtomicU64::new(DEFAULT_TTL_FLOOR_MS);
+static TTL_CEILING_MS: AtomicU64 = AtomicU64::new(DEFAULT_TTL_CEILING_MS);
+static MAX_ENTRIES_PER_SHARD: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_ENTRIES);
+static DUMP_STATS_ON_EXIT: AtomicBool = AtomicBool::new(false);
+static STATS_PATH: Mutex<Option<String>> = Mutex::new(None);
+
+// Read NSCACHE_SHARD_COUNT once at load time. Call this from the ctor,
+// before any shard is ever touched.
+pub fn init_shard_count() {
+    let count = env::var("NSCACHE_SHARD_COUNT")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|&n| n > 0)
+        .unwrap_or(DEFAULT_SHARD_COUNT);
+    SHARD_COUNT.store(count, Ordering::Relaxed);
+}
+
+pub fn shard_count() -> usize {
+    SHARD_COUNT.load(Ordering::Relaxed)
+}
+
+// Read NSCACHE_DEEP_COPY once at load time. When set, the cache hands out
+// deep copies of each cached addrinfo chain instead of sharing one pointer
+// across callers, trading a copy per hit for dropping the refcount/defer
+// machinery entirely.
+pub fn init_deep_copy_mode() {
+    let enabled = env::var("NSCACHE_DEEP_COPY")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    DEEP_COPY_MODE.store(enabled, Ordering::Relaxed);
+}
+
+pub fn deep_copy_mode() -> bool {
+    DEEP_COPY_MODE.load(Ordering::Relaxed)
+}
+
+// Read NSCACHE_USE_DNS_TTL, NSCACHE_TTL_FLOOR_MS and NSCACHE_TTL_CEILING_MS
+// once at load time. When TTL mode is on, a cache entry's lifetime comes
+// from the resolved record's own TTL (clamped to these bounds) instead of
+// the fixed CACHE_LIFETIME_MS.
+pub fn init_ttl_mode() {
+    let enabled = env::var("NSCACHE_USE_DNS_TTL")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    USE_DNS_TTL.store(enabled, Ordering::Relaxed);
+
+    let floor = env::var("NSCACHE_TTL_FLOOR_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TTL_FLOOR_MS);
+    TTL_FLOOR_MS.store(floor, Ordering::Relaxed);
+
+    let ceiling = env::var("NSCACHE_TTL_CEILING_MS")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_TTL_CEILING_MS)
+        .max(floor);
+    TTL_CEILING_MS.store(ceiling, Ordering::Relaxed);
+}
+
+pub fn use_dns_ttl() -> bool {
+    USE_DNS_TTL.load(Ordering::Relaxed)
+}
+
+pub fn clamp_ttl_ms(ttl_ms: u64) -> u64 {
+    ttl_ms.clamp(TTL_FLOOR_MS.load(Ordering::Relaxed), TTL_CEILING_MS.load(Ordering::Relaxed))
+}
+
+// Read NSCACHE_MAX_ENTRIES once at load time: the total number of cached
+// entries across all shards, spread evenly per shard. 0 (the default)
+// means unbounded.
+pub fn init_max_entries() {
+    let total = env::var("NSCACHE_MAX_ENTRIES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_ENTRIES);
+    let per_shard = if total == 0 { 0 } else { (total / shard_count()).max(1) };
+    MAX_ENTRIES_PER_SHARD.store(per_shard, Ordering::Relaxed);
+}
+
+pub fn max_entries_per_shard() -> usize {
+    MAX_ENTRIES_PER_SHARD.load(Ordering::Relaxed)
+}
+
+// Read NSCACHE_STATS_PATH and NSCACHE_DUMP_STATS_ON_EXIT once at load time.
+// NSCACHE_STATS_PATH is where nscache_dump_stats() writes its report
+// (stderr if unset); NSCACHE_DUMP_STATS_ON_EXIT additionally dumps a report
+// there when the process exits.
+pub fn init_stats_config() {
+    *STATS_PATH.lock().unwrap() = env::var("NSCACHE_STATS_PATH").ok();
+
+    let on_exit = env::var("NSCACHE_DUMP_STATS_ON_EXIT")
+        .map(|v| v == "1" || v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false);
+    DUMP_STATS_ON_EXIT.store(on_exit, Ordering::Relaxed);
+}
+
+pub fn stats_path() -> Option<String> {
+    STATS_PATH.lock().unwrap().clone()
+}
+
+pub fn dump_stats_on_exit() -> bool {
+    DUMP_STATS_ON_EXIT.load(Ordering::Relaxed)
+}
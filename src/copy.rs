@@ -0,0 +1,46 @@
+// Deep-copies a cached addrinfo chain so each caller gets an independent,
+// malloc()-backed copy that freeaddrinfo() can release normally, with no
+// need to track how many callers still hold the cache's pointer.
+//
+// glibc's own getaddrinfo() allocates each node and its sockaddr as a
+// single block, with ai_addr pointing just past the addrinfo struct, and
+// its freeaddrinfo() frees only ai_canonname and that one block per node
+// (never ai_addr separately). We have to match that layout: allocating
+// ai_addr as its own malloc() would leak it, since nothing ever frees it.
+
+use libc::{addrinfo, malloc, sockaddr, strdup};
+use std::ptr;
+
+pub unsafe fn deep_copy(src: *const addrinfo) -> *mut addrinfo {
+    if src.is_null() {
+        return ptr::null_mut();
+    }
+
+    let addr_len = if (*src).ai_addr.is_null() { 0 } else { (*src).ai_addrlen as usize };
+    let node = malloc(std::mem::size_of::<addrinfo>() + addr_len) as *mut addrinfo;
+    assert!(!node.is_null(), "out of memory deep-copying addrinfo");
+
+    let ai_addr = if addr_len > 0 {
+        let addr = node.add(1) as *mut u8;
+        ptr::copy_nonoverlapping((*src).ai_addr as *const u8, addr, addr_len);
+        addr as *mut sockaddr
+    } else {
+        ptr::null_mut()
+    };
+
+    ptr::write(
+        node,
+        addrinfo {
+            ai_flags: (*src).ai_flags,
+            ai_family: (*src).ai_family,
+            ai_socktype: (*src).ai_socktype,
+            ai_protocol: (*src).ai_protocol,
+            ai_addrlen: (*src).ai_addrlen,
+            ai_addr,
+            ai_canonname: if (*src).ai_canonname.is_null() { ptr::null_mut() } else { strdup((*src).ai_canonname) },
+            ai_next: deep_copy((*src).ai_next),
+        },
+    );
+
+    node
+}
@@ -2,10 +2,11 @@
 //
 // Data structures
 // ===============
-// - CACHE: Hash table from getaddrinfo() params to responses.
-// - PARAMS: Hash table with pointers to cache keys.
-// - REF_COUNTS: Hash table with pointers to reference counts
-// - DEFER_QUEUE: Queue for deferred deletions.
+// - Shards: N independent shards (see shard.rs), each owning its own
+//   cache, reverse params map, refcount table and defer queue behind a
+//   single Mutex. This lets concurrent resolutions for different
+//   hostnames proceed in parallel instead of serializing on one global
+//   lock.
 //
 // Typically an application performs getaddrinfo()/freeaddrinfo() calls as a pair.
 // If we instantly remove any data once it has no more references, then we never
@@ -14,38 +15,30 @@
 //
 // The idea of the defer list is that a pointer with no references will get free'd
 // only after DEFER_CALL_COUNT calls to freeaddrinfo() has been made.
-//
-// All operations lock the cache prior to any changes, so the code has a global
-// lock to simplify implementation. DNS queries should be rare enough that this
-// should make no difference in practice.
 
-use ctor::ctor;
-use lazy_static::lazy_static;
-use libc::{addrinfo, c_int, c_void, dlsym, AF_UNSPEC, AI_ADDRCONFIG, AI_V4MAPPED, RTLD_NEXT};
-use std::collections::{HashMap, VecDeque};
-use std::ffi::{CStr, CString};
+mod config;
+mod copy;
+mod negative;
+mod params;
+mod shard;
+mod stats;
+mod ttl;
+
+use ctor::{ctor, dtor};
+use libc::{addrinfo, c_void, dlsym, EAI_AGAIN, RTLD_NEXT};
+use params::GetAddrInfoParams;
+use shard::{LookupResult, Response, SHARDS};
+use std::ffi::CString;
 use std::mem::transmute;
 use std::os::raw::c_char;
-use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // How many milliseconds to cache resolver data.
 const CACHE_LIFETIME_MS: u64 = 1000;
 
-// Maximum amount of unfree'd and unused pointers.
-const DEFER_CALL_COUNT: usize = 1000;
-
 type GetAddrInfoFn = fn(*const c_char, *const c_char, *const addrinfo, *mut *mut addrinfo) -> i32;
 type FreeAddrInfoFn = fn(*mut addrinfo);
 
-fn from_raw(chars: *const c_char) -> String {
-    if chars.is_null() {
-        "".to_string()
-    } else {
-        unsafe { CStr::from_ptr(chars).to_str().unwrap().to_string() }
-    }
-}
-
 //
 // Init pointers to original functions
 //
@@ -56,6 +49,11 @@ static mut orig_freeaddrinfo: Option<FreeAddrInfoFn> = None;
 #[ctor]
 fn init() {
     println!("Loading libc DNS resolver cacher");
+    config::init_shard_count();
+    config::init_deep_copy_mode();
+    config::init_ttl_mode();
+    config::init_max_entries();
+    config::init_stats_config();
     unsafe {
         let gai = CString::new("getaddrinfo").expect("CString::new failed");
         let ptr = dlsym(RTLD_NEXT, gai.as_ptr());
@@ -67,141 +65,20 @@ fn init() {
     }
 }
 
-//
-// Cache for responses
-//
-
-#[derive(Eq, Hash, PartialEq, Clone)]
-struct GetAddrInfoParams {
-    hostname: String,
-    servname: String,
-    flags: c_int,
-    family: c_int,
-    socktype: c_int,
-    protocol: c_int,
-}
-
-impl GetAddrInfoParams {
-    fn new(hostname: *const c_char, servname: *const c_char, hints: *const addrinfo) -> Self {
-        if hints.is_null() {
-            Self {
-                hostname: from_raw(hostname),
-                servname: from_raw(servname),
-                socktype: 0,
-                protocol: 0,
-                family: AF_UNSPEC,
-                flags: AI_V4MAPPED | AI_ADDRCONFIG,
-            }
-        } else {
-            Self {
-                hostname: from_raw(hostname),
-                servname: from_raw(servname),
-                socktype: unsafe { (*hints).ai_socktype },
-                protocol: unsafe { (*hints).ai_protocol },
-                family: unsafe { (*hints).ai_family },
-                flags: unsafe { (*hints).ai_flags },
-            }
-        }
-    }
-}
-
-struct Response {
-    timestamp: u64,
-    ai: *mut addrinfo,
-    retval: i32,
-}
-unsafe impl Send for Response {}
-
-#[derive(Eq, Hash, PartialEq)]
-struct AddrInfoWrapper(*mut addrinfo);
-
-unsafe impl Send for AddrInfoWrapper {}
-
-lazy_static! {
-    static ref CACHE: Mutex<HashMap<GetAddrInfoParams, Response>> = Mutex::new(HashMap::new());
-    static ref PARAMS: Mutex<HashMap<AddrInfoWrapper, GetAddrInfoParams>> =
-        Mutex::new(HashMap::new());
-}
-
-//
-// Deferred deletion logic
-//
-
-#[derive(Clone)]
-struct RefCount {
-    refs: i32,
-    deleted: bool,
-}
-
-lazy_static! {
-    static ref REF_COUNTS: Mutex<HashMap<AddrInfoWrapper, RefCount>> = Mutex::new(HashMap::new());
-    static ref DEFER_QUEUE: Mutex<VecDeque<AddrInfoWrapper>> = Mutex::new(VecDeque::new());
-}
-
-fn inc_ref_count(ptr: *mut addrinfo) -> RefCount {
-    let mut ref_counts = REF_COUNTS.lock().unwrap();
-    let ref_key = AddrInfoWrapper(ptr);
-
-    let count = ref_counts.get_mut(&ref_key);
-    match count {
-        Some(count) => {
-            count.refs += 1;
-            RefCount {
-                refs: count.refs,
-                deleted: count.deleted,
-            }
-        }
-        None => {
-            ref_counts.insert(
-                ref_key,
-                RefCount {
-                    refs: 1,
-                    deleted: false,
-                },
-            );
-            RefCount {
-                refs: 1,
-                deleted: false,
-            }
-        }
-    }
-}
-
-fn defer_delete_ptr(ptr: *mut addrinfo) {
-    let mut ref_counts = REF_COUNTS.lock().unwrap();
-    let ref_key = AddrInfoWrapper(ptr);
-
-    let count = ref_counts.get_mut(&ref_key);
-    match count {
-        Some(count) => {
-            count.refs -= 1;
-            if !count.deleted {
-                let mut queue = DEFER_QUEUE.lock().unwrap();
-                queue.push_back(AddrInfoWrapper(ptr));
-                count.deleted = true;
-            }
-        }
-        None => {
-            println!("Logic error: deleting an unknown pointer");
-        }
+// Dumps a stats report on process exit when NSCACHE_DUMP_STATS_ON_EXIT is
+// set, to the same destination nscache_dump_stats() would use.
+#[dtor]
+fn shutdown() {
+    if config::dump_stats_on_exit() {
+        stats::dump(config::stats_path().as_deref());
     }
 }
 
-fn get_ref_count(ptr: *mut addrinfo) -> RefCount {
-    let mut ref_counts = REF_COUNTS.lock().unwrap();
-    let ref_key = AddrInfoWrapper(ptr);
-
-    let count = ref_counts.get_mut(&ref_key);
-    match count {
-        Some(count) => count.clone(),
-        None => {
-            println!("Logic error: asking refcount on unknown pointer");
-            RefCount {
-                refs: -1,
-                deleted: false,
-            }
-        }
-    }
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
 }
 
 //
@@ -215,88 +92,67 @@ pub extern "C" fn getaddrinfo(
     hints: *const addrinfo,
     res: *mut *mut addrinfo,
 ) -> i32 {
-    let mut timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
-
     let params = GetAddrInfoParams::new(hostname, servname, hints);
-    let mut cache = CACHE.lock().unwrap();
 
-    let cached = cache.get(&params);
-    if let Some(value) = cached {
-        if timestamp - value.timestamp < CACHE_LIFETIME_MS {
-            inc_ref_count(value.ai);
+    match SHARDS.lookup(&params, now_ms(), |ptr| unsafe { orig_freeaddrinfo.unwrap()(ptr) }) {
+        LookupResult::Found(retval, ai) => {
             unsafe {
-                *res = value.ai;
+                *res = ai;
             }
-            return value.retval;
+            return retval;
         }
-
-        PARAMS.lock().unwrap().remove(&AddrInfoWrapper(value.ai));
-        cache.remove(&params);
+        LookupResult::NegativeCached(errorcode) => return errorcode,
+        LookupResult::RateLimited => return EAI_AGAIN,
+        LookupResult::Miss => {}
     }
 
-    // Release locks before doing expensive DNS lookup
-    drop(cache);
-
     let retval = unsafe { orig_getaddrinfo.unwrap()(hostname, servname, hints, res) };
 
-    // Do not cache responses that are failures.
     if retval < 0 {
+        SHARDS.record_failure(&params, now_ms(), retval);
         return retval;
     }
 
-    timestamp = SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap()
-        .as_millis() as u64;
+    let ttl_ms = if config::use_dns_ttl() {
+        ttl::probe_ttl_ms(&params.hostname)
+            .map(config::clamp_ttl_ms)
+            .unwrap_or(CACHE_LIFETIME_MS)
+    } else {
+        CACHE_LIFETIME_MS
+    };
 
+    let timestamp = now_ms();
     let response = Response {
         timestamp,
         ai: unsafe { *res },
         retval,
+        ttl_ms,
+        last_access: timestamp,
     };
-    let ai = response.ai;
 
-    let mut cache = CACHE.lock().unwrap();
-
-    // If someone else filled cache before us, remove the value.
-    if let Some(value) = cache.get(&params) {
-        PARAMS.lock().unwrap().remove(&AddrInfoWrapper(value.ai));
-    }
-
-    inc_ref_count(response.ai);
-    cache.insert(params.clone(), response);
-    PARAMS.lock().unwrap().insert(AddrInfoWrapper(ai), params);
+    SHARDS.insert(params, response, |ptr| unsafe { orig_freeaddrinfo.unwrap()(ptr) });
 
     retval
 }
 
 #[no_mangle]
 pub extern "C" fn freeaddrinfo(ai: *mut addrinfo) {
-    // Always grab cache lock, so refcounts do not change while cache lock is held.
-    let mut cache = CACHE.lock().unwrap();
-
-    defer_delete_ptr(ai);
-
-    let mut queue = DEFER_QUEUE.lock().unwrap();
-
-    if queue.len() > DEFER_CALL_COUNT {
-        let deferred = queue.pop_front().unwrap();
-
-        let refs = get_ref_count(deferred.0);
-        if refs.refs > 0 {
-            return;
-        }
+    // In deep-copy mode every caller owns an independent malloc()'d chain;
+    // there is nothing to refcount or defer.
+    if config::deep_copy_mode() {
+        unsafe { orig_freeaddrinfo.unwrap()(ai) }
+        return;
+    }
+    SHARDS.defer_free(ai, |ptr| unsafe { orig_freeaddrinfo.unwrap()(ptr) });
+}
 
-        // Cleanup any cached data about the pointer.
-        REF_COUNTS.lock().unwrap().remove(&deferred);
-        let mut params = PARAMS.lock().unwrap();
-        if let Some(p) = params.remove(&deferred) {
-            cache.remove(&p);
-        }
+//
+// Query symbols
+//
 
-        unsafe { orig_freeaddrinfo.unwrap()(deferred.0) }
-    }
+// Writes a report of cache hits/misses/negative-hits/evictions/expiries and
+// per-shard lock contention to NSCACHE_STATS_PATH, or stderr if unset.
+#[no_mangle]
+pub extern "C" fn nscache_dump_stats() {
+    stats::dump(config::stats_path().as_deref());
 }
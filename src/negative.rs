@@ -0,0 +1,65 @@
+// Negative-result caching with exponential flood protection.
+//
+// A failed getaddrinfo() is remembered for up to NEGATIVE_CACHE_LIFETIME_MS.
+// Within that window a repeated lookup is resolved one of two ways without
+// ever calling the real resolver: if it arrives before `next_allowed` it is
+// RateLimited (the caller gets EAI_AGAIN), otherwise the original error is
+// replayed as NegativeCached. Each time a real query for the same name fails
+// again, the backoff doubles up to NEGATIVE_BACKOFF_MAX_MS, so a name that
+// keeps failing gets queried less and less often.
+
+// How long a failed lookup is remembered before a real query is attempted
+// again. Deliberately shorter than a typical positive TTL.
+pub const NEGATIVE_CACHE_LIFETIME_MS: u64 = 5_000;
+
+const NEGATIVE_BACKOFF_INITIAL_MS: u64 = 250;
+const NEGATIVE_BACKOFF_MAX_MS: u64 = NEGATIVE_CACHE_LIFETIME_MS;
+
+pub struct NegativeEntry {
+    pub errorcode: i32,
+    timestamp: u64,
+    next_allowed: u64,
+    backoff_ms: u64,
+}
+
+pub enum NegativeOutcome {
+    Expired,
+    RateLimited,
+    Cached(i32),
+}
+
+impl NegativeEntry {
+    pub fn first_failure(now: u64, errorcode: i32) -> Self {
+        NegativeEntry {
+            errorcode,
+            timestamp: now,
+            next_allowed: now + NEGATIVE_BACKOFF_INITIAL_MS,
+            backoff_ms: NEGATIVE_BACKOFF_INITIAL_MS,
+        }
+    }
+
+    // Called when a lookup observes this entry again while it has failed
+    // once more in the meantime; grows the backoff for next time.
+    pub fn record_repeat_failure(&mut self, now: u64, errorcode: i32) {
+        self.errorcode = errorcode;
+        self.timestamp = now;
+        self.backoff_ms = (self.backoff_ms * 2).min(NEGATIVE_BACKOFF_MAX_MS);
+        self.next_allowed = now + self.backoff_ms;
+    }
+
+    // Time of the most recent failure, used to pick an eviction candidate
+    // when the negative map is bounded (see shard.rs).
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn check(&self, now: u64) -> NegativeOutcome {
+        if now - self.timestamp >= NEGATIVE_CACHE_LIFETIME_MS {
+            return NegativeOutcome::Expired;
+        }
+        if now < self.next_allowed {
+            return NegativeOutcome::RateLimited;
+        }
+        NegativeOutcome::Cached(self.errorcode)
+    }
+}
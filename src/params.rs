@@ -0,0 +1,46 @@
+// Types describing a getaddrinfo() call, used as the cache key.
+
+use libc::{addrinfo, c_int, AF_UNSPEC, AI_ADDRCONFIG, AI_V4MAPPED};
+use std::os::raw::c_char;
+
+pub fn from_raw(chars: *const c_char) -> String {
+    if chars.is_null() {
+        "".to_string()
+    } else {
+        unsafe { std::ffi::CStr::from_ptr(chars).to_str().unwrap().to_string() }
+    }
+}
+
+#[derive(Eq, Hash, PartialEq, Clone)]
+pub struct GetAddrInfoParams {
+    pub hostname: String,
+    pub servname: String,
+    pub flags: c_int,
+    pub family: c_int,
+    pub socktype: c_int,
+    pub protocol: c_int,
+}
+
+impl GetAddrInfoParams {
+    pub fn new(hostname: *const c_char, servname: *const c_char, hints: *const addrinfo) -> Self {
+        if hints.is_null() {
+            Self {
+                hostname: from_raw(hostname),
+                servname: from_raw(servname),
+                socktype: 0,
+                protocol: 0,
+                family: AF_UNSPEC,
+                flags: AI_V4MAPPED | AI_ADDRCONFIG,
+            }
+        } else {
+            Self {
+                hostname: from_raw(hostname),
+                servname: from_raw(servname),
+                socktype: unsafe { (*hints).ai_socktype },
+                protocol: unsafe { (*hints).ai_protocol },
+                family: unsafe { (*hints).ai_family },
+                flags: unsafe { (*hints).ai_flags },
+            }
+        }
+    }
+}
@@ -0,0 +1,463 @@
+// Sharded cache storage.
+//
+// Each shard owns its own forward cache (params -> response), reverse
+// params map (pointer -> params), refcount table and defer queue, all
+// behind a single Mutex. A lookup picks its shard by hashing the
+// GetAddrInfoParams; freeaddrinfo() only has the addrinfo pointer, so it
+// picks its shard by hashing the pointer instead. Those two hashes can
+// land on different shards for the same cached entry, which is why the
+// reverse/refcount/defer bookkeeping for a pointer lives in the
+// pointer's shard rather than the params' shard.
+
+use crate::config;
+use crate::negative::{NegativeEntry, NegativeOutcome};
+use crate::params::GetAddrInfoParams;
+use libc::addrinfo;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+
+// Maximum amount of unfree'd and unused pointers per shard.
+const DEFER_CALL_COUNT: usize = 1000;
+
+pub struct Response {
+    pub timestamp: u64,
+    pub ai: *mut addrinfo,
+    pub retval: i32,
+    // How long this entry stays valid for, from `timestamp`. Either the
+    // fixed CACHE_LIFETIME_MS or a probed-and-clamped DNS TTL; see ttl.rs.
+    pub ttl_ms: u64,
+    // Updated on every hit; the LRU eviction scan evicts the entry with
+    // the oldest last_access once a shard is full.
+    pub last_access: u64,
+}
+unsafe impl Send for Response {}
+
+#[derive(Eq, Hash, PartialEq, Clone, Copy)]
+pub struct AddrInfoWrapper(pub *mut addrinfo);
+
+unsafe impl Send for AddrInfoWrapper {}
+
+#[derive(Clone)]
+struct RefCount {
+    refs: i32,
+    deleted: bool,
+}
+
+#[derive(Default)]
+pub struct ShardState {
+    cache: HashMap<GetAddrInfoParams, Response>,
+    negative: HashMap<GetAddrInfoParams, NegativeEntry>,
+    params: HashMap<AddrInfoWrapper, GetAddrInfoParams>,
+    ref_counts: HashMap<AddrInfoWrapper, RefCount>,
+    defer_queue: VecDeque<AddrInfoWrapper>,
+}
+
+// Outcome of a cache lookup: either a positive hit, a remembered failure
+// (possibly rate-limited), or a miss that the caller must resolve for real.
+pub enum LookupResult {
+    Found(i32, *mut addrinfo),
+    NegativeCached(i32),
+    RateLimited,
+    Miss,
+}
+
+pub struct Shard {
+    index: usize,
+    state: Mutex<ShardState>,
+}
+
+impl Shard {
+    fn new(index: usize) -> Self {
+        Shard {
+            index,
+            state: Mutex::new(ShardState::default()),
+        }
+    }
+
+    // Locks the shard, counting the acquisition as contended if it had to
+    // wait for a holder to release it first.
+    fn lock(&self) -> std::sync::MutexGuard<'_, ShardState> {
+        match self.state.try_lock() {
+            Ok(guard) => guard,
+            Err(std::sync::TryLockError::WouldBlock) => {
+                crate::stats::record_contention(self.index);
+                self.state.lock().unwrap()
+            }
+            Err(std::sync::TryLockError::Poisoned(err)) => err.into_inner(),
+        }
+    }
+}
+
+pub struct Shards(Vec<Shard>);
+
+impl Shards {
+    fn new() -> Self {
+        let n = config::shard_count();
+        crate::stats::init_shard_contention(n);
+        let shards = (0..n).map(Shard::new).collect();
+        Shards(shards)
+    }
+
+    fn index_for_params(&self, params: &GetAddrInfoParams) -> usize {
+        let mut hasher = DefaultHasher::new();
+        params.hash(&mut hasher);
+        (hasher.finish() as usize) % self.0.len()
+    }
+
+    fn index_for_ptr(&self, ptr: *mut addrinfo) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (ptr as usize).hash(&mut hasher);
+        (hasher.finish() as usize) % self.0.len()
+    }
+
+    fn cache_shard(&self, params: &GetAddrInfoParams) -> &Shard {
+        &self.0[self.index_for_params(params)]
+    }
+
+    fn ptr_shard(&self, ptr: *mut addrinfo) -> &Shard {
+        &self.0[self.index_for_ptr(ptr)]
+    }
+
+    // Looks up a cache hit (positive or negative) and bumps the positive
+    // refcount as a side effect. `free_stale` is called, outside any
+    // shard lock, to release a canonical copy whose expiry we notice here
+    // in deep-copy mode (see copy.rs); it is unused otherwise.
+    pub fn lookup(
+        &self,
+        params: &GetAddrInfoParams,
+        now: u64,
+        free_stale: impl Fn(*mut addrinfo),
+    ) -> LookupResult {
+        let shard = self.cache_shard(params);
+        let mut state = shard.lock();
+
+        let hit = state
+            .cache
+            .get(params)
+            .map(|v| (v.timestamp, v.retval, v.ai, v.ttl_ms));
+        if let Some((timestamp, retval, ai, ttl_ms)) = hit {
+            if now - timestamp < ttl_ms {
+                if let Some(v) = state.cache.get_mut(params) {
+                    v.last_access = now;
+                }
+                crate::stats::record_hit();
+                if config::deep_copy_mode() {
+                    // Copy while cache_shard is still locked: every path
+                    // that frees a canonical copy (insert()'s race-replace,
+                    // evict_if_full, the expiry branch below) removes it
+                    // from `cache` under this same lock before freeing it,
+                    // so holding the lock across the copy guarantees `ai`
+                    // is still live for the duration of deep_copy().
+                    let copy = unsafe { crate::copy::deep_copy(ai) };
+                    drop(state);
+                    return LookupResult::Found(retval, copy);
+                }
+                drop(state);
+                // inc_ref_count() can lose a race with a concurrent
+                // defer_free() drain that reclaims `ai` between our read
+                // above and the bump below -- ptr_shard is a different
+                // lock than cache_shard, so there's no mutual exclusion
+                // between them. Treat a lost race as a miss rather than
+                // handing out a pointer that may already be freed.
+                if self.inc_ref_count(ai) {
+                    return LookupResult::Found(retval, ai);
+                }
+                state = shard.lock();
+                state.cache.remove(params);
+                crate::stats::record_miss();
+                return LookupResult::Miss;
+            }
+
+            crate::stats::record_expiry();
+            state.cache.remove(params);
+            if config::deep_copy_mode() {
+                drop(state);
+                free_stale(ai);
+                state = shard.lock();
+            } else {
+                // Only drop the reverse-map entry, as insert()'s race-replace
+                // does: the refcount insert() creates belongs to the caller
+                // that received `ai`, and callers' freeaddrinfo() calls
+                // reclaim it through the defer queue.
+                drop(state);
+                self.ptr_shard(ai).lock().params.remove(&AddrInfoWrapper(ai));
+                state = shard.lock();
+            }
+        }
+
+        if let Some(entry) = state.negative.get(params) {
+            match entry.check(now) {
+                NegativeOutcome::RateLimited => {
+                    crate::stats::record_rate_limited();
+                    return LookupResult::RateLimited;
+                }
+                NegativeOutcome::Cached(errorcode) => {
+                    crate::stats::record_negative_hit();
+                    return LookupResult::NegativeCached(errorcode);
+                }
+                // Leave the entry in place rather than removing it: the
+                // caller is about to re-query for real, and if that query
+                // fails too we want record_failure() to see this entry and
+                // keep escalating the backoff instead of resetting it back
+                // to the initial value. A successful query clears it via
+                // insert()'s negative.remove() instead.
+                NegativeOutcome::Expired => {}
+            }
+        }
+
+        crate::stats::record_miss();
+        LookupResult::Miss
+    }
+
+    // Records a failed resolution, backing off further on each repeated
+    // failure for the same key.
+    pub fn record_failure(&self, params: &GetAddrInfoParams, now: u64, errorcode: i32) {
+        let shard = self.cache_shard(params);
+        let mut state = shard.lock();
+
+        match state.negative.get_mut(params) {
+            Some(entry) => entry.record_repeat_failure(now, errorcode),
+            None => {
+                Self::evict_negative_if_full(&mut state, now);
+                state
+                    .negative
+                    .insert(params.clone(), NegativeEntry::first_failure(now, errorcode));
+            }
+        }
+    }
+
+    // Keeps `negative` under the same per-shard cap as `cache`: a sustained
+    // outage against many distinct names only ever grows this map (insert()
+    // is never reached), so it needs its own bound rather than relying on
+    // evict_if_full(). Prefers a genuinely expired entry; falls back to the
+    // oldest failure otherwise.
+    fn evict_negative_if_full(state: &mut ShardState, now: u64) {
+        let cap = config::max_entries_per_shard();
+        if cap == 0 || state.negative.len() < cap {
+            return;
+        }
+
+        let expired_key = state
+            .negative
+            .iter()
+            .find(|(_, entry)| matches!(entry.check(now), NegativeOutcome::Expired))
+            .map(|(k, _)| k.clone());
+
+        let key = expired_key.or_else(|| {
+            state
+                .negative
+                .iter()
+                .min_by_key(|(_, entry)| entry.timestamp())
+                .map(|(k, _)| k.clone())
+        });
+
+        if let Some(key) = key {
+            state.negative.remove(&key);
+        }
+    }
+
+    // Evicts expired entries found along the way, then the least-recently-
+    // used entry, until `cache_shard` is back under its cap (a no-op if no
+    // cap was configured). Each removal briefly takes and releases
+    // `cache_shard`'s lock on its own, and reclaims the evicted pointer
+    // without holding it, so that this works even when `cache_shard` and
+    // the pointer's own shard (see ptr_shard()) are the same shard.
+    // `free_evicted` is only invoked in deep-copy mode, where an evicted
+    // entry's canonical copy is the cache's sole reference to it and must be
+    // freed right away. In refcount/defer mode the cache holds no reference
+    // of its own: only the evicted pointer's reverse-map entry is dropped,
+    // as insert()'s race-replace does, and the pointer is reclaimed through
+    // the defer queue once its callers have freed it.
+    fn evict_if_full(&self, cache_shard: &Shard, now: u64, free_evicted: &impl Fn(*mut addrinfo)) {
+        let cap = config::max_entries_per_shard();
+        if cap == 0 {
+            return;
+        }
+
+        loop {
+            let evicted = {
+                let mut state = cache_shard.lock();
+                if state.cache.len() < cap {
+                    break;
+                }
+
+                let expired_key = state
+                    .cache
+                    .iter()
+                    .find(|(_, v)| now - v.timestamp >= v.ttl_ms)
+                    .map(|(k, _)| k.clone());
+                let key = match expired_key {
+                    Some(key) => {
+                        crate::stats::record_expiry();
+                        Some(key)
+                    }
+                    None => {
+                        let lru_key = state.cache.iter().min_by_key(|(_, v)| v.last_access).map(|(k, _)| k.clone());
+                        if lru_key.is_some() {
+                            crate::stats::record_eviction();
+                        }
+                        lru_key
+                    }
+                };
+
+                match key {
+                    Some(key) => state.cache.remove(&key).map(|v| v.ai),
+                    None => break,
+                }
+            };
+
+            if let Some(ai) = evicted {
+                if config::deep_copy_mode() {
+                    free_evicted(ai);
+                } else {
+                    self.ptr_shard(ai).lock().params.remove(&AddrInfoWrapper(ai));
+                }
+            }
+        }
+    }
+
+    // `free_stale` releases a canonical copy displaced by a racing insert
+    // for the same key, or one reclaimed by eviction; only used in
+    // deep-copy mode, where the cache owns its canonical copy outright
+    // instead of sharing it via refcounts.
+    pub fn insert(&self, params: GetAddrInfoParams, response: Response, free_stale: impl Fn(*mut addrinfo)) {
+        if config::deep_copy_mode() {
+            let canonical = Response {
+                timestamp: response.timestamp,
+                ai: unsafe { crate::copy::deep_copy(response.ai) },
+                retval: response.retval,
+                ttl_ms: response.ttl_ms,
+                last_access: response.timestamp,
+            };
+
+            let shard = self.cache_shard(&params);
+            self.evict_if_full(shard, canonical.timestamp, &free_stale);
+
+            let mut state = shard.lock();
+            state.negative.remove(&params);
+            let stale = state.cache.insert(params, canonical);
+            drop(state);
+
+            if let Some(stale) = stale {
+                free_stale(stale.ai);
+            }
+            return;
+        }
+
+        let ai = response.ai;
+        let now = response.timestamp;
+        let shard = self.cache_shard(&params);
+        self.evict_if_full(shard, now, &free_stale);
+
+        {
+            let mut state = shard.lock();
+
+            // If someone else filled the cache before us, drop the
+            // reverse-map entry for their pointer; the defer queue still
+            // owns freeing it once its refcount reaches zero.
+            if let Some(stale) = state.cache.get(&params) {
+                let stale_ai = stale.ai;
+                drop(state);
+                let stale_shard = self.ptr_shard(stale_ai);
+                stale_shard.lock().params.remove(&AddrInfoWrapper(stale_ai));
+                state = shard.lock();
+            }
+
+            let mut response = response;
+            response.last_access = now;
+            state.cache.insert(params.clone(), response);
+            state.negative.remove(&params);
+        }
+
+        {
+            let shard = self.ptr_shard(ai);
+            let mut state = shard.lock();
+            state.params.insert(AddrInfoWrapper(ai), params);
+            state.ref_counts.insert(
+                AddrInfoWrapper(ai),
+                RefCount {
+                    refs: 1,
+                    deleted: false,
+                },
+            );
+        }
+    }
+
+    // Bumps the refcount for an already-cached pointer, returning false if
+    // no entry was found. insert() always creates the ref_counts entry
+    // alongside the cache entry, so a missing one never legitimately means
+    // "first reference" here -- it means a concurrent defer_free() drain
+    // reclaimed the pointer before this call could run, and the caller
+    // must not hand it out.
+    fn inc_ref_count(&self, ptr: *mut addrinfo) -> bool {
+        let shard = self.ptr_shard(ptr);
+        let mut state = shard.lock();
+        let ref_key = AddrInfoWrapper(ptr);
+
+        match state.ref_counts.get_mut(&ref_key) {
+            Some(count) => {
+                count.refs += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Mirrors the original defer_delete_ptr()/DEFER_QUEUE draining logic,
+    // scoped to the pointer's shard, plus the cross-shard cache removal
+    // once a deferred pointer is actually reclaimed.
+    pub fn defer_free(&self, ptr: *mut addrinfo, orig_freeaddrinfo: impl FnOnce(*mut addrinfo)) {
+        let shard = self.ptr_shard(ptr);
+        let mut state = shard.lock();
+        let ref_key = AddrInfoWrapper(ptr);
+
+        let enqueue = match state.ref_counts.get_mut(&ref_key) {
+            Some(count) => {
+                count.refs -= 1;
+                let first_delete = !count.deleted;
+                count.deleted = true;
+                first_delete
+            }
+            None => {
+                println!("Logic error: deleting an unknown pointer");
+                false
+            }
+        };
+        if enqueue {
+            state.defer_queue.push_back(ref_key);
+        }
+
+        if state.defer_queue.len() <= DEFER_CALL_COUNT {
+            return;
+        }
+        let deferred = state.defer_queue.pop_front().unwrap();
+
+        let refs = match state.ref_counts.get(&deferred) {
+            Some(count) => count.refs,
+            None => {
+                println!("Logic error: asking refcount on unknown pointer");
+                -1
+            }
+        };
+        if refs > 0 {
+            return;
+        }
+
+        state.ref_counts.remove(&deferred);
+        let removed_params = state.params.remove(&deferred);
+        drop(state);
+
+        if let Some(p) = removed_params {
+            let cache_shard = self.cache_shard(&p);
+            cache_shard.lock().cache.remove(&p);
+        }
+
+        orig_freeaddrinfo(deferred.0)
+    }
+}
+
+lazy_static::lazy_static! {
+    pub static ref SHARDS: Shards = Shards::new();
+}
@@ -0,0 +1,109 @@
+// Runtime counters, dumped on demand through nscache_dump_stats() (see
+// lib.rs) and optionally on process exit.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+#[derive(Default)]
+pub struct Counters {
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub negative_hits: AtomicU64,
+    pub rate_limited: AtomicU64,
+    pub evictions: AtomicU64,
+    pub expiries: AtomicU64,
+}
+
+impl Counters {
+    fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+static COUNTERS: Counters = Counters {
+    hits: AtomicU64::new(0),
+    misses: AtomicU64::new(0),
+    negative_hits: AtomicU64::new(0),
+    rate_limited: AtomicU64::new(0),
+    evictions: AtomicU64::new(0),
+    expiries: AtomicU64::new(0),
+};
+
+// Per-shard count of lock acquisitions that had to wait because the shard
+// was already held. Sized once at init and never resized afterwards.
+static SHARD_CONTENTION: OnceLock<Vec<AtomicU64>> = OnceLock::new();
+
+pub fn init_shard_contention(shard_count: usize) {
+    let counters = (0..shard_count).map(|_| AtomicU64::new(0)).collect();
+    SHARD_CONTENTION
+        .set(counters)
+        .unwrap_or_else(|_| panic!("init_shard_contention called more than once"));
+}
+
+pub fn record_contention(shard_index: usize) {
+    if let Some(counters) = SHARD_CONTENTION.get() {
+        if let Some(counter) = counters.get(shard_index) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn record_hit() {
+    Counters::bump(&COUNTERS.hits);
+}
+
+pub fn record_miss() {
+    Counters::bump(&COUNTERS.misses);
+}
+
+pub fn record_negative_hit() {
+    Counters::bump(&COUNTERS.negative_hits);
+}
+
+pub fn record_rate_limited() {
+    Counters::bump(&COUNTERS.rate_limited);
+}
+
+pub fn record_eviction() {
+    Counters::bump(&COUNTERS.evictions);
+}
+
+pub fn record_expiry() {
+    Counters::bump(&COUNTERS.expiries);
+}
+
+fn report() -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "libnscache stats:");
+    let _ = writeln!(out, "  hits:           {}", COUNTERS.hits.load(Ordering::Relaxed));
+    let _ = writeln!(out, "  misses:         {}", COUNTERS.misses.load(Ordering::Relaxed));
+    let _ = writeln!(out, "  negative hits:  {}", COUNTERS.negative_hits.load(Ordering::Relaxed));
+    let _ = writeln!(out, "  rate limited:   {}", COUNTERS.rate_limited.load(Ordering::Relaxed));
+    let _ = writeln!(out, "  evictions:      {}", COUNTERS.evictions.load(Ordering::Relaxed));
+    let _ = writeln!(out, "  expiries:       {}", COUNTERS.expiries.load(Ordering::Relaxed));
+    let _ = writeln!(out, "  shard contention (waited-for-lock count per shard):");
+    if let Some(counters) = SHARD_CONTENTION.get() {
+        for (i, counter) in counters.iter().enumerate() {
+            let _ = writeln!(out, "    shard {}: {}", i, counter.load(Ordering::Relaxed));
+        }
+    }
+    out
+}
+
+// Writes the report to `path`, or to stderr if `path` is None or can't be
+// opened.
+pub fn dump(path: Option<&str>) {
+    let report = report();
+
+    if let Some(path) = path {
+        if let Ok(mut file) = File::create(path) {
+            let _ = file.write_all(report.as_bytes());
+            return;
+        }
+    }
+
+    eprint!("{}", report);
+}
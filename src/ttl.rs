@@ -0,0 +1,176 @@
+// Probes the authoritative TTL for a name so a cache entry can expire when
+// the record itself says to, instead of after a fixed lifetime.
+//
+// We issue a low-level query for the A/AAAA records alongside the
+// getaddrinfo() call the application asked for, then walk the wire-format
+// answer by hand (res_nquery gives us the raw message, not a parsed one)
+// and take the minimum TTL across every A/AAAA answer record.
+//
+// res_query()/_res use one process-global resolver state, which isn't
+// safe to share across threads calling into this interposer concurrently.
+// res_ninit()/res_nquery() take an explicit res_state instead, so we keep
+// one per thread and never touch it from more than one thread at a time.
+
+use libc::{c_int, c_uchar};
+use std::cell::RefCell;
+use std::ffi::CString;
+
+// Declared directly against libresolv; not exposed by the `libc` crate.
+// See build.rs for the link directive.
+extern "C" {
+    fn res_ninit(statp: *mut ResState) -> c_int;
+    fn res_nclose(statp: *mut ResState);
+    fn res_nquery(
+        statp: *mut ResState,
+        dname: *const libc::c_char,
+        class: c_int,
+        ty: c_int,
+        answer: *mut c_uchar,
+        anslen: c_int,
+    ) -> c_int;
+}
+
+// Opaque storage for glibc's `struct __res_state`. We never read or write
+// its fields ourselves, only hand the pointer to res_ninit/res_nquery/
+// res_nclose, so we don't need its real layout -- just a buffer generously
+// larger than it, zeroed before the first res_ninit() call.
+const RES_STATE_SIZE: usize = 1024;
+
+#[repr(C, align(8))]
+struct ResState([u8; RES_STATE_SIZE]);
+
+struct Resolver {
+    state: Box<ResState>,
+}
+
+impl Resolver {
+    fn new() -> Option<Self> {
+        let mut state = Box::new(ResState([0u8; RES_STATE_SIZE]));
+        let rc = unsafe { res_ninit(state.as_mut()) };
+        if rc != 0 {
+            return None;
+        }
+        Some(Resolver { state })
+    }
+
+    fn query(&mut self, name: &CString, qtype: c_int, buf: &mut [u8]) -> c_int {
+        unsafe {
+            res_nquery(
+                self.state.as_mut(),
+                name.as_ptr(),
+                NS_C_IN,
+                qtype,
+                buf.as_mut_ptr(),
+                buf.len() as c_int,
+            )
+        }
+    }
+}
+
+impl Drop for Resolver {
+    fn drop(&mut self) {
+        unsafe { res_nclose(self.state.as_mut()) };
+    }
+}
+
+thread_local! {
+    static RESOLVER: RefCell<Option<Resolver>> = const { RefCell::new(None) };
+}
+
+const NS_C_IN: c_int = 1;
+const NS_T_A: c_int = 1;
+const NS_T_AAAA: c_int = 28;
+
+const DNS_HEADER_LEN: usize = 12;
+
+// Skips one DNS-encoded name starting at `offset`, returning the offset of
+// the byte right after it. Handles label sequences and the single
+// compression pointer that may terminate one.
+fn skip_name(buf: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *buf.get(offset)? as usize;
+        if len == 0 {
+            return Some(offset + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            // Two-byte compression pointer; it always ends the name here.
+            return Some(offset + 2);
+        }
+        offset += 1 + len;
+    }
+}
+
+// Minimum TTL, in seconds, across every A/AAAA answer record in `buf`, or
+// None if the message is malformed or has no matching records.
+fn min_ttl_seconds(buf: &[u8]) -> Option<u32> {
+    if buf.len() < DNS_HEADER_LEN {
+        return None;
+    }
+    let qdcount = u16::from_be_bytes([buf[4], buf[5]]) as usize;
+    let ancount = u16::from_be_bytes([buf[6], buf[7]]) as usize;
+
+    let mut offset = DNS_HEADER_LEN;
+    for _ in 0..qdcount {
+        offset = skip_name(buf, offset)?;
+        offset += 4; // qtype + qclass
+    }
+
+    let mut min_ttl: Option<u32> = None;
+    for _ in 0..ancount {
+        offset = skip_name(buf, offset)?;
+        let rtype = u16::from_be_bytes([*buf.get(offset)?, *buf.get(offset + 1)?]);
+        let ttl = u32::from_be_bytes([
+            *buf.get(offset + 4)?,
+            *buf.get(offset + 5)?,
+            *buf.get(offset + 6)?,
+            *buf.get(offset + 7)?,
+        ]);
+        let rdlength = u16::from_be_bytes([*buf.get(offset + 8)?, *buf.get(offset + 9)?]) as usize;
+        offset += 10 + rdlength;
+
+        if rtype as c_int == NS_T_A || rtype as c_int == NS_T_AAAA {
+            min_ttl = Some(min_ttl.map_or(ttl, |m: u32| m.min(ttl)));
+        }
+    }
+    min_ttl
+}
+
+fn query(hostname: &str, qtype: c_int) -> Option<u32> {
+    let name = CString::new(hostname).ok()?;
+    let mut buf = [0u8; 2048];
+
+    let len = RESOLVER.with(|cell| {
+        let mut slot = cell.borrow_mut();
+        if slot.is_none() {
+            *slot = Resolver::new();
+        }
+        slot.as_mut().map(|resolver| resolver.query(&name, qtype, &mut buf))
+    })?;
+
+    if len <= 0 {
+        return None;
+    }
+    // res_nquery() can report a length greater than `anslen` for an answer
+    // that was truncated and would need a TCP retry (large A/AAAA sets,
+    // DNSSEC) -- it's telling us how big the answer really is, not how much
+    // it wrote into `buf`. Clamp to what we actually have before slicing, or
+    // this panics inside the interposed getaddrinfo() on an otherwise-valid
+    // lookup.
+    let n = (len as usize).min(buf.len());
+    min_ttl_seconds(&buf[..n])
+}
+
+// Minimum TTL across A and AAAA records for `hostname`, in milliseconds,
+// or None if both probes failed or returned nothing usable.
+pub fn probe_ttl_ms(hostname: &str) -> Option<u64> {
+    if hostname.is_empty() {
+        return None;
+    }
+    let ttl = match (query(hostname, NS_T_A), query(hostname, NS_T_AAAA)) {
+        (Some(a), Some(aaaa)) => a.min(aaaa),
+        (Some(a), None) => a,
+        (None, Some(aaaa)) => aaaa,
+        (None, None) => return None,
+    };
+    Some(ttl as u64 * 1000)
+}